@@ -1,4 +1,10 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use bytes::Bytes;
 use futures_util::{SinkExt, StreamExt};
@@ -20,7 +26,7 @@ use tokio::{sync::oneshot, task::JoinHandle};
 use tokio_tungstenite::{connect_async, tungstenite::client::IntoClientRequest};
 use tracing::error;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde_json::{Value, json};
 
 type HttpClient = Client<hyper_rustls::HttpsConnector<HttpConnector>, Body>;
@@ -40,6 +46,40 @@ pub struct ProxyConfig {
     pub backend_scheme: Scheme,
     pub morph_domain_suffix: Option<String>,
     pub workspace_domain_suffix: Option<String>,
+    /// Maximum time to wait on an upstream before giving up with a
+    /// `504 Gateway Timeout`. Applies to the forwarded HTTP request, the
+    /// HEAD-fallback GET, and the WebSocket upstream connect.
+    pub upstream_timeout: Duration,
+    /// How the upstream TLS certificate is verified. Defaults to the public
+    /// webpki root set; operators can add roots or pin a DNS name for
+    /// self-signed morph/workspace hosts.
+    pub upstream_tls: UpstreamTlsConfig,
+    /// Optional path to a JSON route table loaded at startup. Matching hosts
+    /// are proxied per the table; anything unmatched falls through to the
+    /// built-in cmux subdomain routing.
+    pub route_table_path: Option<PathBuf>,
+    /// Extra origins (exact host, optionally with port) allowed to make
+    /// credentialed cross-origin requests, in addition to the active cmux zone.
+    pub cors_allowlist: Vec<String>,
+    /// Enable the in-memory response cache for idempotent GET requests. Off by
+    /// default so the proxy stays stateless unless opted in.
+    pub cache_enabled: bool,
+}
+
+/// TLS verification options for upstream (morph/workspace) connections.
+///
+/// With both fields unset the proxy trusts only the publicly-distributed
+/// webpki roots, matching the historical behaviour. Setting either one builds
+/// a bespoke rustls `ClientConfig` so internal VMs whose certificates are not
+/// in the public root set can still be reached.
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamTlsConfig {
+    /// Additional root CA certificates (PEM encoded) to trust alongside the
+    /// webpki roots.
+    pub extra_root_cas_pem: Option<Vec<u8>>,
+    /// When set, the upstream certificate is validated against this DNS name
+    /// instead of the (possibly synthesized) request authority.
+    pub fixed_server_name: Option<String>,
 }
 
 impl Default for ProxyConfig {
@@ -50,6 +90,11 @@ impl Default for ProxyConfig {
             backend_scheme: Scheme::HTTP,
             morph_domain_suffix: None,
             workspace_domain_suffix: None,
+            upstream_timeout: Duration::from_secs(60),
+            upstream_tls: UpstreamTlsConfig::default(),
+            route_table_path: None,
+            cors_allowlist: Vec::new(),
+            cache_enabled: false,
         }
     }
 }
@@ -75,6 +120,333 @@ pub enum ProxyError {
     Io(#[from] std::io::Error),
     #[error("hyper error: {0}")]
     Hyper(#[from] hyper::Error),
+    #[error("tls configuration error: {0}")]
+    Tls(String),
+    #[error("route table error: {0}")]
+    Config(String),
+}
+
+/// A single declarative route loaded from the route-table file. Hosts are
+/// matched in file order; the first match wins.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct RouteRule {
+    /// Host pattern to match against the incoming request host. A leading
+    /// `*.` makes it a suffix match (e.g. `*.preview.example.com`); otherwise
+    /// it is an exact, case-insensitive host match.
+    pub host: String,
+    #[serde(flatten)]
+    pub target: RouteTarget,
+    #[serde(default)]
+    pub behavior: RouteBehaviorConfig,
+}
+
+/// Where a matched route sends traffic: either a port on the configured
+/// backend host, or an absolute scheme/host/port.
+///
+/// `Absolute` is declared first so an `{scheme, upstream_host, port}` entry is
+/// matched by it rather than collapsing to a bare `Port` (which requires only
+/// `port`). The absolute host field is named `upstream_host` so it does not
+/// clash with the flattened [`RouteRule::host`] match pattern, which previously
+/// consumed the `host` key and left `Absolute` unreachable.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum RouteTarget {
+    Absolute {
+        scheme: String,
+        upstream_host: String,
+        #[serde(default)]
+        port: Option<u16>,
+    },
+    Port {
+        port: u16,
+    },
+}
+
+/// Per-route CORS/CSP/service-worker knobs mirroring the internal
+/// [`ProxyBehavior`] so operators can tune behaviour without recompiling.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct RouteBehaviorConfig {
+    #[serde(default)]
+    pub skip_service_worker: bool,
+    #[serde(default)]
+    pub add_cors: bool,
+    #[serde(default)]
+    pub strip_cors_headers: bool,
+    #[serde(default)]
+    pub frame_ancestors: Option<String>,
+    #[serde(default)]
+    pub workspace_header: Option<String>,
+    #[serde(default)]
+    pub port_header: Option<String>,
+    #[serde(default)]
+    pub optimize_images: bool,
+}
+
+fn load_route_table(path: &std::path::Path) -> Result<Vec<RouteRule>, ProxyError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| ProxyError::Config(format!("failed to read {}: {err}", path.display())))?;
+    serde_json::from_str(&contents)
+        .map_err(|err| ProxyError::Config(format!("failed to parse {}: {err}", path.display())))
+}
+
+/// Upper bound on cached entries. Past this the oldest-stored entry is evicted
+/// on insert so an enabled cache cannot grow without bound on a long-lived proxy.
+const MAX_CACHE_ENTRIES: usize = 1024;
+
+/// A small in-memory cache of transformed responses, keyed by request identity.
+/// Only populated when caching is enabled; each entry remembers the freshness
+/// lifetime derived from the upstream caching headers and any validator so a
+/// stale hit can be revalidated with a conditional request.
+#[derive(Default)]
+struct ResponseCache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+/// The outcome of a cache lookup.
+enum CacheLookup {
+    /// A still-fresh entry that can be served without touching the upstream.
+    Fresh(CacheEntry),
+    /// A stale entry carrying a validator; revalidate it with a conditional
+    /// request before reuse.
+    Stale(CacheEntry),
+    /// No usable entry (absent, the `Vary`-selected headers differ, or stale
+    /// with no validator to revalidate it).
+    Miss,
+}
+
+#[derive(Clone)]
+struct CacheEntry {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: DateTime<Utc>,
+    freshness: Duration,
+    must_revalidate: bool,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    /// Lowercased request-header names named in the response `Vary`, paired with
+    /// the values the entry was stored under, so lookups with differing values
+    /// miss instead of serving a mismatched representation.
+    vary: Vec<(String, Option<String>)>,
+}
+
+impl ResponseCache {
+    fn lookup(&self, key: &str, req_headers: &HeaderMap, now: DateTime<Utc>) -> CacheLookup {
+        let entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get(key) else {
+            return CacheLookup::Miss;
+        };
+        if !entry.vary_matches(req_headers) {
+            return CacheLookup::Miss;
+        }
+        if entry.is_fresh(now) {
+            CacheLookup::Fresh(entry.clone())
+        } else if entry.etag.is_some() || entry.last_modified.is_some() {
+            CacheLookup::Stale(entry.clone())
+        } else {
+            CacheLookup::Miss
+        }
+    }
+
+    fn store(&self, key: String, entry: CacheEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        // Re-inserting an existing key never grows the map, so only evict when
+        // adding a genuinely new key would exceed the cap. Drop the
+        // oldest-stored entry first, approximating an LRU without a separate
+        // recency index.
+        if entries.len() >= MAX_CACHE_ENTRIES && !entries.contains_key(&key) {
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored_at)
+                .map(|(key, _)| key.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, entry);
+    }
+
+    /// Refreshes a stored entry after a `304 Not Modified`, adopting the new
+    /// freshness window and any updated validators while keeping the cached body.
+    fn refresh(&self, key: &str, not_modified: &HeaderMap, now: DateTime<Utc>) -> Option<CacheEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        let meta = CacheMeta::from_headers(not_modified);
+        entry.stored_at = now;
+        entry.freshness = meta.freshness;
+        entry.must_revalidate = meta.no_cache;
+        if let Some(etag) = meta.etag {
+            entry.etag = Some(etag);
+        }
+        if let Some(last_modified) = meta.last_modified {
+            entry.last_modified = Some(last_modified);
+        }
+        Some(entry.clone())
+    }
+}
+
+impl CacheEntry {
+    fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        if self.must_revalidate {
+            return false;
+        }
+        match chrono::Duration::from_std(self.freshness) {
+            Ok(lifetime) => now < self.stored_at + lifetime,
+            Err(_) => false,
+        }
+    }
+
+    fn vary_matches(&self, req_headers: &HeaderMap) -> bool {
+        self.vary.iter().all(|(name, stored)| {
+            let current = req_headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string());
+            &current == stored
+        })
+    }
+
+    /// Adds `If-None-Match`/`If-Modified-Since` from this entry's validators so
+    /// the upstream can answer `304` for a stale-but-unchanged resource.
+    fn apply_conditional_headers(&self, headers: &mut HeaderMap) {
+        if let Some(etag) = self.etag.as_ref() {
+            headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.last_modified.as_ref() {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    fn to_response(&self) -> Response<Body> {
+        let mut builder = Response::builder()
+            .status(self.status)
+            .version(self.version);
+        let headers_mut = builder.headers_mut().unwrap();
+        for (name, value) in self.headers.iter() {
+            headers_mut.insert(name, value.clone());
+        }
+        builder.body(Body::from(self.body.clone())).unwrap()
+    }
+}
+
+/// Cache directives distilled from an upstream response's `Cache-Control`,
+/// `Expires`, and `Age` headers, plus the validators needed to revalidate.
+struct CacheMeta {
+    /// Whether the response may be stored at all (`no-store`/`private` forbid it).
+    storable: bool,
+    /// `no-cache` forces revalidation on every reuse even within the lifetime.
+    no_cache: bool,
+    freshness: Duration,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl CacheMeta {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let directives = parse_cache_control(headers);
+        let age = headers
+            .get(header::AGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+
+        // max-age (or the shared-cache s-maxage) wins over Expires; fall back to
+        // Expires - Date when no max-age is present.
+        let lifetime = directives
+            .max_age
+            .or(directives.s_maxage)
+            .map(|max_age| max_age.saturating_sub(age))
+            .or_else(|| expires_lifetime(headers));
+
+        let vary = parse_vary(headers);
+
+        // A `Vary: *` response is uncacheable: it can never be matched back.
+        let storable =
+            !directives.no_store && !directives.private && !vary.iter().any(|v| v == "*");
+
+        Self {
+            storable,
+            no_cache: directives.no_cache,
+            freshness: Duration::from_secs(lifetime.unwrap_or(0)),
+            etag: headers.get(header::ETAG).cloned(),
+            last_modified: headers.get(header::LAST_MODIFIED).cloned(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+fn parse_cache_control(headers: &HeaderMap) -> CacheControl {
+    let mut control = CacheControl::default();
+    let Some(value) = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return control;
+    };
+
+    for token in value.split(',') {
+        let token = token.trim();
+        let (name, arg) = match token.split_once('=') {
+            Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+            None => (token, None),
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" => control.no_store = true,
+            "no-cache" => control.no_cache = true,
+            "private" => control.private = true,
+            "max-age" => control.max_age = arg.and_then(|a| a.parse().ok()),
+            "s-maxage" => control.s_maxage = arg.and_then(|a| a.parse().ok()),
+            _ => {}
+        }
+    }
+    control
+}
+
+/// Seconds of freshness implied by `Expires` relative to the response `Date`
+/// (or the current time when `Date` is absent). A past expiry yields zero.
+fn expires_lifetime(headers: &HeaderMap) -> Option<u64> {
+    let expires = headers
+        .get(header::EXPIRES)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())?;
+    let baseline = headers
+        .get(header::DATE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .map(|date| date.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now);
+    let delta = expires.with_timezone(&Utc) - baseline;
+    Some(delta.num_seconds().max(0) as u64)
+}
+
+/// Cache key for a request: authority, method, and path+query. `Vary`-selected
+/// request headers are verified separately against the matched entry.
+fn cache_key(authority: &str, method: &Method, path_and_query: &str) -> String {
+    format!("{authority}\n{method}\n{path_and_query}")
+}
+
+/// Lowercased header names listed in a response `Vary`, empty tokens dropped.
+fn parse_vary(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get(header::VARY)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .map(|token| token.trim().to_ascii_lowercase())
+                .filter(|token| !token.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default()
 }
 
 struct AppState {
@@ -83,6 +455,12 @@ struct AppState {
     backend_scheme: Scheme,
     morph_domain_suffix: Option<String>,
     workspace_domain_suffix: Option<String>,
+    upstream_timeout: Duration,
+    routes: Vec<RouteRule>,
+    cors_allowlist: Vec<String>,
+    /// In-memory response cache for idempotent GET requests. `None` unless
+    /// `cache_enabled` was set in the config.
+    cache: Option<Arc<ResponseCache>>,
 }
 
 pub async fn spawn_proxy(config: ProxyConfig) -> Result<ProxyHandle, ProxyError> {
@@ -90,27 +468,35 @@ pub async fn spawn_proxy(config: ProxyConfig) -> Result<ProxyHandle, ProxyError>
     listener.set_nonblocking(true)?;
     let local_addr = listener.local_addr()?;
 
-    let https = HttpsConnectorBuilder::new()
-        .with_webpki_roots()
-        .https_or_http()
-        .enable_http1()
-        .build();
+    let https = build_https_connector(&config.upstream_tls)?;
     let client: HttpClient = Client::builder().build(https);
 
+    let routes = match config.route_table_path.as_ref() {
+        Some(path) => load_route_table(path)?,
+        None => Vec::new(),
+    };
+
     let state = Arc::new(AppState {
         client,
         backend_host: config.backend_host,
         backend_scheme: config.backend_scheme,
         morph_domain_suffix: config.morph_domain_suffix,
         workspace_domain_suffix: config.workspace_domain_suffix,
+        upstream_timeout: config.upstream_timeout,
+        routes,
+        cors_allowlist: config.cors_allowlist,
+        cache: config
+            .cache_enabled
+            .then(|| Arc::new(ResponseCache::default())),
     });
 
-    let make_svc = make_service_fn(move |_conn: &AddrStream| {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
         let state = state.clone();
+        let client_ip = conn.remote_addr().ip();
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
                 let state = state.clone();
-                async move { Ok::<_, hyper::Error>(handle_request(state, req).await) }
+                async move { Ok::<_, hyper::Error>(handle_request(state, client_ip, req).await) }
             }))
         }
     });
@@ -133,7 +519,104 @@ pub async fn spawn_proxy(config: ProxyConfig) -> Result<ProxyHandle, ProxyError>
     })
 }
 
-async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Body> {
+/// Builds the HTTPS connector used for upstream requests. With no TLS options
+/// configured this reproduces the historical `with_webpki_roots()` connector;
+/// otherwise it assembles a rustls `ClientConfig` from the supplied roots and
+/// optional pinned server name.
+fn build_https_connector(
+    tls: &UpstreamTlsConfig,
+) -> Result<hyper_rustls::HttpsConnector<HttpConnector>, ProxyError> {
+    if tls.extra_root_cas_pem.is_none() && tls.fixed_server_name.is_none() {
+        return Ok(HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build());
+    }
+
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    if let Some(pem) = tls.extra_root_cas_pem.as_ref() {
+        let mut reader = std::io::Cursor::new(pem);
+        let certs = rustls_pemfile::certs(&mut reader)
+            .map_err(|err| ProxyError::Tls(format!("failed to read root CA bundle: {err}")))?;
+        if certs.is_empty() {
+            return Err(ProxyError::Tls(
+                "root CA bundle contained no certificates".to_string(),
+            ));
+        }
+        for cert in certs {
+            root_store
+                .add(&rustls::Certificate(cert))
+                .map_err(|err| ProxyError::Tls(format!("invalid root CA certificate: {err}")))?;
+        }
+    }
+
+    let mut tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store.clone())
+        .with_no_client_auth();
+
+    if let Some(name) = tls.fixed_server_name.as_ref() {
+        let server_name = rustls::ServerName::try_from(name.as_str())
+            .map_err(|err| ProxyError::Tls(format!("invalid fixed server name {name:?}: {err}")))?;
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(FixedNameVerifier {
+                inner: rustls::client::WebPkiVerifier::new(root_store, None),
+                server_name,
+            }));
+    }
+
+    Ok(HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1()
+        .build())
+}
+
+/// Delegates to the standard webpki verifier but pins the DNS name the
+/// certificate is validated against. This lets a request to a synthesized
+/// `port-…-morphvm-…` authority be checked against the backend's real
+/// certificate name instead.
+struct FixedNameVerifier {
+    inner: rustls::client::WebPkiVerifier,
+    server_name: rustls::ServerName,
+}
+
+impl rustls::client::ServerCertVerifier for FixedNameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            &self.server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+async fn handle_request(
+    state: Arc<AppState>,
+    client_ip: IpAddr,
+    req: Request<Body>,
+) -> Response<Body> {
     if req.uri().path() == "/health" {
         return json_response(
             StatusCode::OK,
@@ -171,7 +654,41 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
         }
     }
 
-    if let Some((subdomain, _domain)) = parse_cmux_host(&host) {
+    if let Some(rule) = match_route(&state.routes, &host) {
+        if is_loop_header(&req) {
+            return text_response(StatusCode::LOOP_DETECTED, "Loop detected in proxy");
+        }
+
+        let target = match &rule.target {
+            RouteTarget::Port { port } => Target::BackendPort(*port),
+            RouteTarget::Absolute {
+                scheme,
+                upstream_host,
+                port,
+            } => Target::Absolute {
+                scheme: parse_scheme(scheme),
+                host: upstream_host.clone(),
+                port: *port,
+            },
+        };
+
+        let cors_origin = resolve_cors_origin(&state.cors_allowlist, None, request_origin(&req));
+        let behavior = ProxyBehavior {
+            skip_service_worker: rule.behavior.skip_service_worker,
+            add_cors: rule.behavior.add_cors,
+            strip_cors_headers: rule.behavior.strip_cors_headers,
+            workspace_header: rule.behavior.workspace_header.clone(),
+            port_header: rule.behavior.port_header.clone(),
+            frame_ancestors: rule.behavior.frame_ancestors.clone(),
+            cors_origin,
+            optimize_images: rule.behavior.optimize_images,
+            accept_header: accept_header(&req),
+        };
+
+        return handle_forward(state, client_ip, req, target, behavior).await;
+    }
+
+    if let Some((subdomain, domain)) = parse_cmux_host(&host) {
         if subdomain.is_none() {
             return text_response(StatusCode::OK, "cmux!");
         }
@@ -180,6 +697,10 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
             return service_worker_response();
         }
 
+        let cors_origin =
+            resolve_cors_origin(&state.cors_allowlist, Some(&domain), request_origin(&req));
+        let client_accept = accept_header(&req);
+
         match parse_route(subdomain.unwrap()) {
             Route::Port(route) => {
                 if is_loop_header(&req) {
@@ -205,13 +726,14 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
                 };
 
                 let (strip_cors_headers, frame_ancestors) = if route.skip_service_worker {
-                    (true, Some(CSP_FRAME_ANCESTORS_PORT_39378))
+                    (true, Some(CSP_FRAME_ANCESTORS_PORT_39378.to_string()))
                 } else {
                     (false, None)
                 };
 
-                return forward_request(
+                return handle_forward(
                     state,
+                    client_ip,
                     req,
                     target,
                     ProxyBehavior {
@@ -221,6 +743,9 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
                         workspace_header: None,
                         port_header: None,
                         frame_ancestors,
+                        cors_origin: cors_origin.clone(),
+                        optimize_images: false,
+                        accept_header: client_accept.clone(),
                     },
                 )
                 .await;
@@ -239,7 +764,11 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
                             .body(Body::empty())
                             .unwrap();
                     }
-                    return cors_response(StatusCode::NO_CONTENT);
+                    return cors_response(
+                        StatusCode::NO_CONTENT,
+                        &req,
+                        cors_origin.as_deref(),
+                    );
                 }
 
                 let target = if let Some(suffix) = state.morph_domain_suffix.clone() {
@@ -253,8 +782,9 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
                     Target::BackendPort(route.port)
                 };
 
-                return forward_request(
+                return handle_forward(
                     state,
+                    client_ip,
                     req,
                     target,
                     ProxyBehavior {
@@ -264,6 +794,9 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
                         workspace_header: route.workspace_header,
                         port_header: Some(route.port.to_string()),
                         frame_ancestors: None,
+                        cors_origin: cors_origin.clone(),
+                        optimize_images: false,
+                        accept_header: client_accept.clone(),
                     },
                 )
                 .await;
@@ -284,8 +817,9 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
                     Target::BackendPort(route.port)
                 };
 
-                return forward_request(
+                return handle_forward(
                     state,
+                    client_ip,
                     req,
                     target,
                     ProxyBehavior {
@@ -295,6 +829,9 @@ async fn handle_request(state: Arc<AppState>, req: Request<Body>) -> Response<Bo
                         workspace_header: Some(route.workspace),
                         port_header: Some(route.port.to_string()),
                         frame_ancestors: None,
+                        cors_origin,
+                        optimize_images: false,
+                        accept_header: client_accept,
                     },
                 )
                 .await;
@@ -316,6 +853,118 @@ enum Target {
     },
 }
 
+impl Target {
+    /// Resolves the target to a concrete `(scheme, host, port)`, filling in the
+    /// configured backend for `BackendPort` variants.
+    fn resolve(&self, state: &AppState) -> (Scheme, String, Option<u16>) {
+        match self {
+            Target::BackendPort(port) => (
+                state.backend_scheme.clone(),
+                state.backend_host.clone(),
+                Some(*port),
+            ),
+            Target::Absolute { scheme, host, port } => (scheme.clone(), host.clone(), *port),
+        }
+    }
+
+    /// The authority (`host[:port]`) this target forwards to, used for Host
+    /// rewriting and error diagnostics.
+    fn authority(&self, state: &AppState) -> String {
+        let (_, host, port_opt) = self.resolve(state);
+        match port_opt {
+            Some(port) => format!("{}:{}", host, port),
+            None => host,
+        }
+    }
+}
+
+/// Typed failure for the forward paths. Each variant maps to a specific status
+/// in [`forward_error_response`] so callers get actionable diagnostics instead
+/// of a blanket `502`.
+#[derive(thiserror::Error, Debug)]
+enum ForwardError {
+    #[error("failed to build upstream URI")]
+    UriBuild,
+    #[error("upstream request failed: {0}")]
+    Upstream(#[source] hyper::Error),
+    #[error("upstream timed out")]
+    Timeout,
+    #[error("failed to upgrade websocket connection")]
+    WebSocketUpgrade,
+    #[error("failed to read upstream body: {0}")]
+    BodyRead(#[source] hyper::Error),
+}
+
+impl ForwardError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ForwardError::UriBuild => StatusCode::INTERNAL_SERVER_ERROR,
+            ForwardError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ForwardError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ForwardError::WebSocketUpgrade => StatusCode::INTERNAL_SERVER_ERROR,
+            ForwardError::BodyRead(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Stable machine-readable slug surfaced in the JSON error body.
+    fn kind(&self) -> &'static str {
+        match self {
+            ForwardError::UriBuild => "uri_build",
+            ForwardError::Upstream(_) => "upstream",
+            ForwardError::Timeout => "timeout",
+            ForwardError::WebSocketUpgrade => "websocket_upgrade",
+            ForwardError::BodyRead(_) => "body_read",
+        }
+    }
+}
+
+/// Wraps a forward path, turning any [`ForwardError`] into a typed JSON
+/// response. This is the single place a `ForwardError` is mapped to a status
+/// and body. A HEAD-fallback GET that times out and an HTML rewrite failure do
+/// not go through here: they are answered with plain-text `504` and `500`
+/// responses built in `handle_head_method_not_allowed` and
+/// `transform_response`.
+async fn handle_forward(
+    state: Arc<AppState>,
+    client_ip: IpAddr,
+    req: Request<Body>,
+    target: Target,
+    behavior: ProxyBehavior,
+) -> Response<Body> {
+    let authority = target.authority(&state);
+    match forward_request(state, client_ip, req, target, behavior).await {
+        Ok(resp) => resp,
+        Err(err) => forward_error_response(&err, &authority),
+    }
+}
+
+fn forward_error_response(err: &ForwardError, authority: &str) -> Response<Body> {
+    let status = err.status_code();
+    let request_id = new_request_id();
+    error!(%err, %authority, %request_id, "upstream forward failed");
+
+    let body = json!({
+        "error": err.kind(),
+        "message": err.to_string(),
+        "authority": authority,
+        "request_id": request_id,
+    });
+
+    let mut response = json_response(status, body);
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert("x-cmux-request-id", value);
+    }
+    response
+}
+
+/// A best-effort unique id for correlating a failed request across logs and
+/// the error body. Derived from the wall clock; uniqueness is sufficient for
+/// diagnostics, not a security token.
+fn new_request_id() -> String {
+    let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    format!("{:016x}", nanos as u64)
+}
+
 #[derive(Clone)]
 struct ProxyBehavior {
     skip_service_worker: bool,
@@ -323,32 +972,38 @@ struct ProxyBehavior {
     strip_cors_headers: bool,
     workspace_header: Option<String>,
     port_header: Option<String>,
-    frame_ancestors: Option<&'static str>,
+    frame_ancestors: Option<String>,
+    /// Origin to reflect in `Access-Control-Allow-Origin` when CORS is added.
+    /// `None` falls back to a credential-less `*`.
+    cors_origin: Option<String>,
+    /// Transcode JPEG/PNG responses to AVIF when the client advertises
+    /// `image/avif`, to cut bandwidth for asset-heavy preview apps. WebP is
+    /// never emitted; see [`optimize_image`].
+    optimize_images: bool,
+    /// Raw client `Accept` header, consulted to pick an image target format.
+    accept_header: Option<String>,
 }
 
 async fn forward_request(
     state: Arc<AppState>,
+    client_ip: IpAddr,
     mut req: Request<Body>,
     target: Target,
     behavior: ProxyBehavior,
-) -> Response<Body> {
+) -> Result<Response<Body>, ForwardError> {
     if is_upgrade_request(&req) {
-        return handle_websocket(state, req, target, behavior).await;
+        return handle_websocket(state, client_ip, req, target, behavior).await;
     }
 
-    let (scheme, host, port_opt) = match target {
-        Target::BackendPort(port) => (
-            state.backend_scheme.clone(),
-            state.backend_host.clone(),
-            Some(port),
-        ),
-        Target::Absolute { scheme, host, port } => (scheme, host, port),
-    };
+    let inbound_scheme = inbound_scheme(&req);
+    let original_host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
 
-    let authority = match port_opt {
-        Some(port) => format!("{}:{}", host, port),
-        None => host,
-    };
+    let (scheme, _host, _port_opt) = target.resolve(&state);
+    let authority = target.authority(&state);
 
     let path_and_query = req
         .uri()
@@ -358,13 +1013,26 @@ async fn forward_request(
     let target_uri =
         match format!("{}://{}{}", scheme.as_str(), authority, path_and_query).parse::<Uri>() {
             Ok(uri) => uri,
-            Err(_) => {
-                return text_response(StatusCode::BAD_GATEWAY, "Failed to build upstream URI");
-            }
+            Err(_) => return Err(ForwardError::UriBuild),
         };
 
     *req.uri_mut() = target_uri;
 
+    // Strip connection-scoped headers before relaying upstream. The WebSocket
+    // handshake is handled above via `handle_websocket`, so this path is never
+    // an upgrade and can drop `Connection`/`Upgrade` unconditionally.
+    let forwarded_headers = remove_hop_headers(req.headers());
+    *req.headers_mut() = forwarded_headers;
+
+    // Record the originating client before the Host header is rewritten to the
+    // backend authority below.
+    apply_forwarded_headers(
+        req.headers_mut(),
+        client_ip,
+        original_host.as_deref(),
+        &inbound_scheme,
+    );
+
     if let Ok(value) = HeaderValue::from_str(&authority) {
         req.headers_mut().insert(header::HOST, value);
     }
@@ -399,11 +1067,49 @@ async fn forward_request(
         None
     };
 
-    let response = match state.client.request(req).await {
-        Ok(resp) => resp,
-        Err(_) => return text_response(StatusCode::BAD_GATEWAY, "Upstream fetch failed"),
+    // Response cache: only idempotent GETs are eligible. A fresh hit short
+    // circuits the upstream entirely; a stale hit with a validator revalidates
+    // via the conditional headers added below.
+    let cache = (original_method == Method::GET)
+        .then(|| state.cache.clone())
+        .flatten();
+    let cache_key = cache
+        .as_ref()
+        .map(|_| cache_key(&authority, &original_method, path_and_query));
+    let now = Utc::now();
+    let stale_revalidation = match (&cache, &cache_key) {
+        (Some(cache), Some(key)) => match cache.lookup(key, req.headers(), now) {
+            CacheLookup::Fresh(entry) => return Ok(entry.to_response()),
+            CacheLookup::Stale(entry) => {
+                entry.apply_conditional_headers(req.headers_mut());
+                true
+            }
+            CacheLookup::Miss => false,
+        },
+        _ => false,
     };
 
+    // Snapshot the forwarded request headers before `req` is consumed, so a
+    // cache store can record the `Vary`-selected values the entry keys on.
+    let req_headers = cache.as_ref().map(|_| req.headers().clone());
+
+    let response =
+        match tokio::time::timeout(state.upstream_timeout, state.client.request(req)).await {
+            Ok(Ok(resp)) => resp,
+            Ok(Err(err)) => return Err(ForwardError::Upstream(err)),
+            Err(_) => return Err(ForwardError::Timeout),
+        };
+
+    // A `304` confirms our stale entry is still current: refresh its age/window
+    // and serve the cached body.
+    if stale_revalidation && response.status() == StatusCode::NOT_MODIFIED {
+        if let (Some(cache), Some(key)) = (&cache, &cache_key) {
+            if let Some(entry) = cache.refresh(key, response.headers(), now) {
+                return Ok(entry.to_response());
+            }
+        }
+    }
+
     if original_method == Method::HEAD
         && matches!(
             response.status(),
@@ -414,12 +1120,100 @@ async fn forward_request(
             if let Some(fallback) =
                 handle_head_method_not_allowed(state, context, behavior.clone()).await
             {
-                return fallback;
+                return Ok(fallback);
             }
         }
     }
 
-    transform_response(response, behavior).await
+    // Capture validators and cache directives from the original upstream
+    // headers before `transform_response` runs `sanitize_headers`, which strips
+    // `etag`. Responses that get CORS headers added are never cached: their
+    // `Access-Control-Allow-Origin` (and credential flag) is origin-dependent,
+    // and a request without an `Origin` would otherwise store a wildcard entry
+    // that a later allowed-origin request could wrongly hit.
+    let store_context = match (&cache, &cache_key) {
+        (Some(cache), Some(key)) if !behavior.add_cors => {
+            let meta = CacheMeta::from_headers(response.headers());
+            let cacheable = meta.storable && response.status() == StatusCode::OK;
+            cacheable.then(|| (cache.clone(), key.clone(), meta))
+        }
+        _ => None,
+    };
+
+    let transformed = transform_response(response, behavior).await?;
+
+    let Some((cache, key, meta)) = store_context else {
+        return Ok(transformed);
+    };
+
+    // Buffer the transformed response so it can be both stored and served. Only
+    // done for responses already deemed cacheable, so the streaming pass-through
+    // path is untouched.
+    let status = transformed.status();
+    let version = transformed.version();
+    let headers = transformed.headers().clone();
+    let body = body::to_bytes(transformed.into_body())
+        .await
+        .map_err(ForwardError::BodyRead)?;
+
+    // Skip caching anything that went through `rewrite_html`: its injected
+    // scripts are per-deployment and must not be replayed. `transform_response`
+    // rewrites both declared `text/html` and generic bodies that sniff as HTML,
+    // so mirror that same decision on the transformed body rather than trusting
+    // the declared content type alone.
+    let is_html = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.contains("text/html"))
+        .unwrap_or(false)
+        || sniff_is_html(&body);
+    if is_html {
+        let mut builder = Response::builder().status(status).version(version);
+        let headers_mut = builder.headers_mut().unwrap();
+        for (name, value) in headers.iter() {
+            headers_mut.insert(name, value.clone());
+        }
+        return Ok(builder.body(Body::from(body)).unwrap());
+    }
+
+    // Key the entry on the transformed response's `Vary`: record the forwarded
+    // request's value for each header it names, so a later lookup whose values
+    // differ misses instead of reusing this representation. Responses that had
+    // CORS headers added never reach this point (see `store_context` above).
+    let req_headers = req_headers.unwrap_or_default();
+    let vary = parse_vary(&headers)
+        .into_iter()
+        .map(|name| {
+            let value = req_headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string());
+            (name, value)
+        })
+        .collect();
+
+    cache.store(
+        key,
+        CacheEntry {
+            status,
+            version,
+            headers: headers.clone(),
+            body: body.clone(),
+            stored_at: now,
+            freshness: meta.freshness,
+            must_revalidate: meta.no_cache,
+            etag: meta.etag,
+            last_modified: meta.last_modified,
+            vary,
+        },
+    );
+
+    let mut builder = Response::builder().status(status).version(version);
+    let headers_mut = builder.headers_mut().unwrap();
+    for (name, value) in headers.iter() {
+        headers_mut.insert(name, value.clone());
+    }
+    Ok(builder.body(Body::from(body)).unwrap())
 }
 
 /// Captures enough of the original HEAD request to retry with GET when the
@@ -445,27 +1239,33 @@ async fn handle_head_method_not_allowed(
     *get_request.headers_mut() = context.headers;
     get_request.headers_mut().remove(header::CONTENT_LENGTH);
 
-    match state.client.request(get_request).await {
-        Ok(resp) => match transform_head_response_from_get(resp, behavior).await {
+    match tokio::time::timeout(state.upstream_timeout, state.client.request(get_request)).await {
+        Ok(Ok(resp)) => match transform_head_response_from_get(resp, behavior).await {
             Ok(head_response) => Some(head_response),
             Err(_) => None,
         },
-        Err(_) => None,
+        Ok(Err(_)) => None,
+        Err(_) => Some(text_response(
+            StatusCode::GATEWAY_TIMEOUT,
+            "Upstream did not respond within the configured timeout",
+        )),
     }
 }
 
 async fn transform_head_response_from_get(
     response: Response<Body>,
     behavior: ProxyBehavior,
-) -> Result<Response<Body>, hyper::Error> {
-    let transformed_response = transform_response(response, behavior.clone()).await;
+) -> Result<Response<Body>, ForwardError> {
+    let transformed_response = transform_response(response, behavior.clone()).await?;
     let status = transformed_response.status();
     let version = transformed_response.version();
     let headers = transformed_response.headers().clone();
 
     // Drain the transformed body so we can surface an accurate Content-Length
     // header that matches the rewritten GET response.
-    let body_bytes = body::to_bytes(transformed_response.into_body()).await?;
+    let body_bytes = body::to_bytes(transformed_response.into_body())
+        .await
+        .map_err(ForwardError::BodyRead)?;
     let body_len = body_bytes.len();
 
     Ok(build_head_response(
@@ -496,12 +1296,12 @@ fn build_head_response(
     if behavior.strip_cors_headers {
         strip_cors_headers(&mut new_headers);
     } else if behavior.add_cors {
-        add_cors_headers(&mut new_headers);
+        add_cors_headers(&mut new_headers, behavior.cors_origin.as_deref());
     }
     if force_cors_headers && !behavior.strip_cors_headers {
-        add_cors_headers(&mut new_headers);
+        add_cors_headers(&mut new_headers, behavior.cors_origin.as_deref());
     }
-    if let Some(frame_ancestors) = behavior.frame_ancestors {
+    if let Some(frame_ancestors) = behavior.frame_ancestors.as_deref() {
         if let Ok(value) = HeaderValue::from_str(frame_ancestors) {
             new_headers.insert("content-security-policy", value);
         }
@@ -520,23 +1320,19 @@ fn build_head_response(
 
 async fn handle_websocket(
     state: Arc<AppState>,
+    client_ip: IpAddr,
     req: Request<Body>,
     target: Target,
     behavior: ProxyBehavior,
-) -> Response<Body> {
-    let (scheme, host, port_opt) = match target {
-        Target::BackendPort(port) => (
-            state.backend_scheme.clone(),
-            state.backend_host.clone(),
-            Some(port),
-        ),
-        Target::Absolute { scheme, host, port } => (scheme, host, port),
-    };
-
-    let authority = match port_opt {
-        Some(port) => format!("{}:{}", host, port),
-        None => host,
-    };
+) -> Result<Response<Body>, ForwardError> {
+    let inbound_scheme = inbound_scheme(&req);
+    let original_host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    let (scheme, _host, _port_opt) = target.resolve(&state);
+    let authority = target.authority(&state);
 
     let path_and_query = req
         .uri()
@@ -549,23 +1345,60 @@ async fn handle_websocket(
     };
     let backend_url = format!("{}://{}{}", ws_scheme, authority, path_and_query);
 
-    let headers_to_forward = collect_forward_headers(req.headers(), &behavior);
+    let mut headers_to_forward = collect_forward_headers(req.headers(), &behavior);
+    apply_forwarded_headers(
+        &mut headers_to_forward,
+        client_ip,
+        original_host.as_deref(),
+        &inbound_scheme,
+    );
+
+    let upstream_timeout = state.upstream_timeout;
+
+    // `hyper_tungstenite::upgrade` builds the client-facing `101` before we
+    // connect upstream, so it cannot know which subprotocol the backend will
+    // select. We forward the client's full offer upstream (see
+    // `collect_forward_headers`). We only echo a subprotocol back in the client
+    // handshake when the client offered exactly one: then the backend either
+    // accepts that single protocol or none, so asserting it cannot put client
+    // and backend into silent disagreement. A multi-protocol offer is left
+    // unanswered rather than guessing the backend's choice.
+    let negotiated_protocol = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .map(|token| token.trim())
+                .filter(|token| !token.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .filter(|offered| offered.len() == 1)
+        .map(|offered| offered[0].to_string());
 
     match hyper_tungstenite::upgrade(req, None) {
-        Ok((response, websocket)) => {
+        Ok((mut response, websocket)) => {
+            if let Some(protocol) = negotiated_protocol {
+                if let Ok(value) = HeaderValue::from_str(&protocol) {
+                    response
+                        .headers_mut()
+                        .insert(header::SEC_WEBSOCKET_PROTOCOL, value);
+                }
+            }
             tokio::spawn(async move {
-                if let Err(err) = pump_websocket(websocket, backend_url, headers_to_forward).await {
+                if let Err(err) =
+                    pump_websocket(websocket, backend_url, headers_to_forward, upstream_timeout)
+                        .await
+                {
                     error!(%err, "websocket proxy error");
                 }
             });
-            response
+            Ok(response)
         }
         Err(err) => {
             error!(%err, "failed to upgrade connection");
-            text_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to upgrade WebSocket connection",
-            )
+            Err(ForwardError::WebSocketUpgrade)
         }
     }
 }
@@ -591,9 +1424,24 @@ fn collect_forward_headers(
     }
     headers.insert("X-Cmux-Proxied", HeaderValue::from_static("true"));
 
+    // Carry forward any prior proxy hops so `apply_forwarded_headers` can append
+    // the current client IP onto the existing chain.
+    if let Some(value) = original.get("x-forwarded-for") {
+        headers.insert("x-forwarded-for", value.clone());
+    }
+
     if let Some(value) = original.get(header::USER_AGENT) {
         headers.insert(header::USER_AGENT, value.clone());
     }
+
+    // Preserve the requested subprotocol so dev-server sockets (Vite HMR,
+    // terminals) that negotiate one survive the proxy. The rest of the
+    // `Sec-WebSocket-*` handshake (key, version, accept) is regenerated by
+    // tungstenite for the upstream leg, so we deliberately do not copy it.
+    if let Some(value) = original.get(header::SEC_WEBSOCKET_PROTOCOL) {
+        headers.insert(header::SEC_WEBSOCKET_PROTOCOL, value.clone());
+    }
+
     headers
 }
 
@@ -639,6 +1487,7 @@ async fn pump_websocket(
     websocket: HyperWebsocket,
     backend_url: String,
     headers: http::HeaderMap,
+    upstream_timeout: Duration,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let client_ws = websocket.await?;
 
@@ -658,7 +1507,15 @@ async fn pump_websocket(
         request.headers_mut().insert(name.clone(), value.clone());
     }
 
-    let (backend_ws, _) = connect_async(request).await?;
+    let (backend_ws, _) = match tokio::time::timeout(upstream_timeout, connect_async(request)).await
+    {
+        Ok(result) => result?,
+        Err(_) => {
+            // Bail out of the pump so the spawned task ends cleanly instead of
+            // leaving the client socket dangling on a stuck upstream connect.
+            return Err("timed out connecting to upstream websocket".into());
+        }
+    };
 
     let (mut client_sink, mut client_stream) = client_ws.split();
     let (mut backend_sink, mut backend_stream) = backend_ws.split();
@@ -703,7 +1560,10 @@ async fn pump_websocket(
     Ok(())
 }
 
-async fn transform_response(response: Response<Body>, behavior: ProxyBehavior) -> Response<Body> {
+async fn transform_response(
+    response: Response<Body>,
+    behavior: ProxyBehavior,
+) -> Result<Response<Body>, ForwardError> {
     let status = response.status();
     let version = response.version();
     let headers = response.headers().clone();
@@ -713,38 +1573,143 @@ async fn transform_response(response: Response<Body>, behavior: ProxyBehavior) -
         .and_then(|v| v.to_str().ok())
         .unwrap_or("");
 
-    if content_type.contains("text/html") {
+    // Trust an explicit `text/html`; for absent or deliberately generic content
+    // types, sniff the buffered body so mislabeled pages still get rewritten and
+    // mislabeled binaries are never corrupted by injection.
+    let declared_html = content_type.contains("text/html");
+    let needs_sniff = !declared_html && is_generic_content_type(content_type);
+
+    if declared_html || needs_sniff {
         match body::to_bytes(response.into_body()).await {
-            Ok(bytes) => match rewrite_html(bytes, behavior.skip_service_worker) {
-                Ok(body) => {
+            Ok(bytes) => {
+                // The rewriter needs plaintext: if the origin compressed the
+                // body, inflate it first so `lol_html` doesn't choke on bytes.
+                // `sanitize_headers(.., true)` drops `content-encoding` below,
+                // so the rewritten body is re-emitted uncompressed.
+                let encoding = headers
+                    .get(header::CONTENT_ENCODING)
+                    .and_then(|v| v.to_str().ok());
+                let decoded = decode_body(&bytes, encoding).unwrap_or_else(|_| bytes.to_vec());
+
+                // Only confirmed HTML is rewritten. A generic content type that
+                // does not sniff as HTML (plain text, a mislabeled binary) is
+                // streamed through untouched in its original encoding.
+                if !declared_html && !sniff_is_html(&decoded) {
                     let mut builder = Response::builder().status(status).version(version);
                     let mut new_headers =
-                        sanitize_headers(&headers, /* strip_payload_headers */ true);
+                        sanitize_headers(&headers, /* strip_payload_headers */ false);
                     strip_csp_headers(&mut new_headers);
                     if behavior.strip_cors_headers {
                         strip_cors_headers(&mut new_headers);
                     } else if behavior.add_cors {
-                        add_cors_headers(&mut new_headers);
+                        add_cors_headers(&mut new_headers, behavior.cors_origin.as_deref());
                     }
-                    if let Some(frame_ancestors) = behavior.frame_ancestors {
+                    if let Some(frame_ancestors) = behavior.frame_ancestors.as_deref() {
                         if let Ok(value) = HeaderValue::from_str(frame_ancestors) {
                             new_headers.insert("content-security-policy", value);
                         }
                     }
-                    new_headers.insert(
-                        header::CONTENT_LENGTH,
-                        HeaderValue::from_str(&body.len().to_string()).unwrap(),
-                    );
                     let headers_mut = builder.headers_mut().unwrap();
                     for (name, value) in new_headers.iter() {
                         headers_mut.insert(name, value.clone());
                     }
-                    builder.body(Body::from(body)).unwrap()
+                    return Ok(builder.body(Body::from(bytes)).unwrap());
                 }
-                Err(_) => text_response(StatusCode::INTERNAL_SERVER_ERROR, "HTML rewrite failed"),
-            },
-            Err(_) => text_response(StatusCode::BAD_GATEWAY, "Failed to read upstream body"),
+
+                match rewrite_html(Bytes::from(decoded), behavior.skip_service_worker) {
+                    Ok(body) => {
+                        let mut builder = Response::builder().status(status).version(version);
+                        let mut new_headers =
+                            sanitize_headers(&headers, /* strip_payload_headers */ true);
+                        strip_csp_headers(&mut new_headers);
+                        if behavior.strip_cors_headers {
+                            strip_cors_headers(&mut new_headers);
+                        } else if behavior.add_cors {
+                            add_cors_headers(&mut new_headers, behavior.cors_origin.as_deref());
+                        }
+                        if let Some(frame_ancestors) = behavior.frame_ancestors.as_deref() {
+                            if let Ok(value) = HeaderValue::from_str(frame_ancestors) {
+                                new_headers.insert("content-security-policy", value);
+                            }
+                        }
+                        new_headers.insert(
+                            header::CONTENT_LENGTH,
+                            HeaderValue::from_str(&body.len().to_string()).unwrap(),
+                        );
+                        let headers_mut = builder.headers_mut().unwrap();
+                        for (name, value) in new_headers.iter() {
+                            headers_mut.insert(name, value.clone());
+                        }
+                        Ok(builder.body(Body::from(body)).unwrap())
+                    }
+                    Err(_) => Ok(text_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "HTML rewrite failed",
+                    )),
+                }
+            }
+            Err(err) => Err(ForwardError::BodyRead(err)),
+        }
+    } else if behavior.optimize_images
+        && (content_type.contains("image/jpeg") || content_type.contains("image/png"))
+    {
+        let accept = behavior.accept_header.clone().unwrap_or_default();
+        let content_type = content_type.to_string();
+        let bytes = body::to_bytes(response.into_body())
+            .await
+            .map_err(ForwardError::BodyRead)?;
+
+        let mut builder = Response::builder().status(status).version(version);
+        // Strip payload metadata either way: a transcode changes the bytes, and
+        // even a pass-through re-emits the same body under recomputed length.
+        let mut new_headers = sanitize_headers(&headers, /* strip_payload_headers */ true);
+        strip_csp_headers(&mut new_headers);
+        if behavior.strip_cors_headers {
+            strip_cors_headers(&mut new_headers);
+        } else if behavior.add_cors {
+            add_cors_headers(&mut new_headers, behavior.cors_origin.as_deref());
         }
+
+        // Decoding and AVIF encoding are CPU-bound and can take hundreds of
+        // milliseconds on a large image; run them on the blocking pool so a
+        // single transcode never stalls the async worker serving other
+        // connections. A panic in the codec falls through to pass-through.
+        let optimized = {
+            let bytes = bytes.clone();
+            let content_type = content_type.clone();
+            let accept = accept.clone();
+            tokio::task::spawn_blocking(move || {
+                optimize_image(&bytes, &content_type, &accept, MAX_IMAGE_OPTIMIZE_DIMENSION)
+            })
+            .await
+            .ok()
+            .flatten()
+        };
+
+        let body = match optimized {
+            Some((encoded, new_content_type)) => {
+                new_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(new_content_type));
+                Bytes::from(encoded)
+            }
+            // Unsupported format, oversized, or a decode failure: pass the
+            // original image through untouched.
+            None => {
+                if let Ok(value) = HeaderValue::from_str(&content_type) {
+                    new_headers.insert(header::CONTENT_TYPE, value);
+                }
+                bytes
+            }
+        };
+
+        new_headers.insert(
+            header::CONTENT_LENGTH,
+            HeaderValue::from_str(&body.len().to_string()).unwrap(),
+        );
+        let headers_mut = builder.headers_mut().unwrap();
+        for (name, value) in new_headers.iter() {
+            headers_mut.insert(name, value.clone());
+        }
+        Ok(builder.body(Body::from(body)).unwrap())
     } else {
         let mut builder = Response::builder().status(status).version(version);
         let mut new_headers = sanitize_headers(&headers, /* strip_payload_headers */ false);
@@ -752,9 +1717,9 @@ async fn transform_response(response: Response<Body>, behavior: ProxyBehavior) -
         if behavior.strip_cors_headers {
             strip_cors_headers(&mut new_headers);
         } else if behavior.add_cors {
-            add_cors_headers(&mut new_headers);
+            add_cors_headers(&mut new_headers, behavior.cors_origin.as_deref());
         }
-        if let Some(frame_ancestors) = behavior.frame_ancestors {
+        if let Some(frame_ancestors) = behavior.frame_ancestors.as_deref() {
             if let Ok(value) = HeaderValue::from_str(frame_ancestors) {
                 new_headers.insert("content-security-policy", value);
             }
@@ -763,7 +1728,7 @@ async fn transform_response(response: Response<Body>, behavior: ProxyBehavior) -
         for (name, value) in new_headers.iter() {
             headers_mut.insert(name, value.clone());
         }
-        builder.body(response.into_body()).unwrap()
+        Ok(builder.body(response.into_body()).unwrap())
     }
 }
 
@@ -777,8 +1742,9 @@ fn sanitize_headers(headers: &HeaderMap, strip_payload_headers: bool) -> HeaderM
         "etag",
     ];
 
+    let without_hop = remove_hop_headers(headers);
     let mut out = HeaderMap::new();
-    for (name, value) in headers.iter() {
+    for (name, value) in without_hop.iter() {
         if strip_payload_headers && ignored_payload_headers.contains(&name.as_str()) {
             continue;
         }
@@ -787,6 +1753,115 @@ fn sanitize_headers(headers: &HeaderMap, strip_payload_headers: bool) -> HeaderM
     out
 }
 
+/// Connection-scoped headers that must never survive a proxy hop (RFC 2616
+/// §13.5.1). `transfer-encoding` also appears in the payload list above; it is
+/// included here so it is dropped on the request path too.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Tokens named in the peer's `Connection` header value, lowercased. Each one
+/// names a further header that is connection-scoped for this hop and must be
+/// removed alongside the fixed list.
+fn connection_tokens(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get(header::CONNECTION)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .map(|token| token.trim().to_ascii_lowercase())
+                .filter(|token| !token.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Copies `headers`, dropping the fixed hop-by-hop set plus anything named in
+/// the incoming `Connection` header. The WebSocket path never routes through
+/// here — it assembles its upgrade headers via `collect_forward_headers` — so
+/// `Connection`/`Upgrade` are always safe to drop.
+fn remove_hop_headers(headers: &HeaderMap) -> HeaderMap {
+    let connection_named = connection_tokens(headers);
+    let mut out = HeaderMap::new();
+    for (name, value) in headers.iter() {
+        let lower = name.as_str().to_ascii_lowercase();
+        if HOP_BY_HOP_HEADERS.contains(&lower.as_str()) {
+            continue;
+        }
+        if connection_named.iter().any(|token| token == &lower) {
+            continue;
+        }
+        out.insert(name.clone(), value.clone());
+    }
+    out
+}
+
+/// Scheme the client used to reach the proxy. Honours an upstream
+/// `X-Forwarded-Proto` set by a TLS terminator in front of us, otherwise falls
+/// back to the scheme on the request URI.
+fn inbound_scheme(req: &Request<Body>) -> String {
+    if let Some(proto) = req
+        .headers()
+        .get("x-forwarded-proto")
+        .and_then(|value| value.to_str().ok())
+    {
+        if let Some(first) = proto.split(',').next() {
+            let trimmed = first.trim();
+            if !trimmed.is_empty() {
+                return trimmed.to_ascii_lowercase();
+            }
+        }
+    }
+
+    match req.uri().scheme_str() {
+        Some("https") | Some("wss") => "https".to_string(),
+        _ => "http".to_string(),
+    }
+}
+
+/// Appends the standard reverse-proxy forwarding headers so workspace backends
+/// can log the originating client and perform origin checks. The client IP is
+/// appended to any existing `X-Forwarded-For` as a new hop, `X-Forwarded-Proto`
+/// reflects the inbound scheme, and `X-Forwarded-Host` preserves the
+/// client-facing authority before it is rewritten to the backend.
+fn apply_forwarded_headers(
+    headers: &mut HeaderMap,
+    client_ip: IpAddr,
+    original_host: Option<&str>,
+    inbound_scheme: &str,
+) {
+    let forwarded_for = match headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(existing) if !existing.trim().is_empty() => {
+            format!("{}, {}", existing, client_ip)
+        }
+        _ => client_ip.to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert("x-forwarded-for", value);
+    }
+
+    if let Ok(value) = HeaderValue::from_str(inbound_scheme) {
+        headers.insert("x-forwarded-proto", value);
+    }
+
+    if let Some(host) = original_host {
+        if let Ok(value) = HeaderValue::from_str(host) {
+            headers.insert("x-forwarded-host", value);
+        }
+    }
+}
+
 fn strip_csp_headers(headers: &mut HeaderMap) {
     headers.remove("content-security-policy");
     headers.remove("content-security-policy-report-only");
@@ -794,8 +1869,24 @@ fn strip_csp_headers(headers: &mut HeaderMap) {
     headers.remove("frame-options");
 }
 
-fn add_cors_headers(headers: &mut HeaderMap) {
-    headers.insert("access-control-allow-origin", HeaderValue::from_static("*"));
+fn add_cors_headers(headers: &mut HeaderMap, origin: Option<&str>) {
+    match origin {
+        // A matched origin is echoed verbatim so `allow-credentials: true` is
+        // valid (browsers reject credentials alongside a `*` origin).
+        Some(origin) => {
+            if let Ok(value) = HeaderValue::from_str(origin) {
+                headers.insert("access-control-allow-origin", value);
+            }
+            append_vary_origin(headers);
+            headers.insert(
+                "access-control-allow-credentials",
+                HeaderValue::from_static("true"),
+            );
+        }
+        None => {
+            headers.insert("access-control-allow-origin", HeaderValue::from_static("*"));
+        }
+    }
     headers.insert(
         "access-control-allow-methods",
         HeaderValue::from_static("GET, POST, PUT, DELETE, PATCH, OPTIONS, HEAD"),
@@ -808,13 +1899,68 @@ fn add_cors_headers(headers: &mut HeaderMap) {
         "access-control-expose-headers",
         HeaderValue::from_static("*"),
     );
-    headers.insert(
-        "access-control-allow-credentials",
-        HeaderValue::from_static("true"),
-    );
     headers.insert("access-control-max-age", HeaderValue::from_static("86400"));
 }
 
+/// Adds `Origin` to the `Vary` header without clobbering existing tokens, so
+/// caches key the reflected origin correctly.
+fn append_vary_origin(headers: &mut HeaderMap) {
+    match headers.get(header::VARY).and_then(|v| v.to_str().ok()) {
+        Some(existing)
+            if existing
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("origin")) => {}
+        Some(existing) => {
+            if let Ok(value) = HeaderValue::from_str(&format!("{existing}, Origin")) {
+                headers.insert(header::VARY, value);
+            }
+        }
+        None => {
+            headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+        }
+    }
+}
+
+/// The request's `Origin` header, if present and valid as a string.
+fn request_origin(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(header::ORIGIN)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn accept_header(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// Reflects the request `Origin` when it is allowed: an exact match in
+/// `allowlist` (host, optionally with port) or a host inside the active cmux
+/// `zone` (suffix match). Returns the origin to echo, or `None` otherwise.
+fn resolve_cors_origin(
+    allowlist: &[String],
+    zone: Option<&str>,
+    origin: Option<&str>,
+) -> Option<String> {
+    let origin = origin?;
+    let authority = origin
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(origin)
+        .to_ascii_lowercase();
+    let host = normalize_host(&authority);
+
+    let allowed = allowlist
+        .iter()
+        .any(|entry| entry.eq_ignore_ascii_case(&authority) || entry.eq_ignore_ascii_case(&host))
+        || zone
+            .map(|zone| host == zone || host.ends_with(&format!(".{zone}")))
+            .unwrap_or(false);
+
+    allowed.then(|| origin.to_string())
+}
+
 fn strip_cors_headers(headers: &mut HeaderMap) {
     const CORS_HEADER_NAMES: &[&str] = &[
         "access-control-allow-origin",
@@ -831,6 +1977,152 @@ fn strip_cors_headers(headers: &mut HeaderMap) {
     }
 }
 
+/// Images larger than this in either dimension are streamed through untouched
+/// rather than transcoded, to bound the CPU and memory a single request can use.
+const MAX_IMAGE_OPTIMIZE_DIMENSION: u32 = 4096;
+
+/// Transcodes a JPEG/PNG body to AVIF when the client advertises it. Returns
+/// the encoded bytes and the new content type, or `None` to leave the response
+/// untouched (unsupported source, AVIF not accepted, oversized, a decode/encode
+/// failure, or a result no smaller than the original).
+///
+/// Only AVIF is emitted: the `image` crate's WebP encoder is lossless, so
+/// re-encoding a lossy JPEG through it reliably *grows* the payload, defeating
+/// the point. AVIF's lossy encoder genuinely shrinks photographic input, and
+/// the final size check below guarantees we never ship a larger body than we
+/// received.
+fn optimize_image(
+    bytes: &[u8],
+    content_type: &str,
+    accept: &str,
+    max_dimension: u32,
+) -> Option<(Vec<u8>, &'static str)> {
+    if !(content_type.contains("image/jpeg") || content_type.contains("image/png")) {
+        return None;
+    }
+
+    if !accept.contains("image/avif") {
+        return None;
+    }
+
+    // Read just the image header to bound the decode: rejecting an oversized
+    // image by its declared dimensions avoids allocating the full pixel buffer
+    // for something we would discard anyway.
+    let reader = image::io::Reader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?;
+    let (width, height) = reader.into_dimensions().ok()?;
+    if width > max_dimension || height > max_dimension {
+        return None;
+    }
+
+    let image = image::load_from_memory(bytes).ok()?;
+
+    let mut out = Vec::new();
+    let encoder = image::codecs::avif::AvifEncoder::new(&mut out);
+    image.write_with_encoder(encoder).ok()?;
+
+    // Never substitute a body that isn't actually smaller — a transcode can
+    // lose to an already well-compressed source.
+    (out.len() < bytes.len()).then_some((out, "image/avif"))
+}
+
+/// Inflates a compressed upstream body to plaintext. Recognises `gzip`,
+/// `deflate`, and `br`; any other (or absent) encoding returns the bytes
+/// unchanged. Used only on the HTML-rewrite/sniffing branch (declared
+/// `text/html` or a generic content type), so responses with any other
+/// content type are never decoded.
+fn decode_body(bytes: &[u8], encoding: Option<&str>) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    match encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+        Some("gzip") | Some("x-gzip") => {
+            let mut decoder = flate2::read::GzDecoder::new(bytes);
+            let mut out = Vec::new();
+            decoder.read_to_end(&mut out)?;
+            Ok(out)
+        }
+        Some("deflate") => {
+            let mut decoder = flate2::read::ZlibDecoder::new(bytes);
+            let mut out = Vec::new();
+            decoder.read_to_end(&mut out)?;
+            Ok(out)
+        }
+        Some("br") => {
+            let mut decoder = brotli::Decompressor::new(bytes, 4096);
+            let mut out = Vec::new();
+            decoder.read_to_end(&mut out)?;
+            Ok(out)
+        }
+        _ => Ok(bytes.to_vec()),
+    }
+}
+
+/// Content types that don't reliably describe the body: absent (empty), or
+/// deliberately generic. Responses carrying one of these are sniffed from their
+/// leading bytes rather than trusted at face value.
+fn is_generic_content_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    essence.is_empty()
+        || essence == "application/octet-stream"
+        || essence == "text/plain"
+        || essence == "unknown/unknown"
+}
+
+/// Sniffs whether a buffered body is HTML. A UTF-8/UTF-16 BOM and leading
+/// whitespace are skipped, known binary magic numbers short-circuit to "not
+/// HTML", and the remaining prefix is matched case-insensitively against the
+/// common document openings.
+fn sniff_is_html(bytes: &[u8]) -> bool {
+    let body = strip_bom(bytes);
+    if looks_like_binary(body) {
+        return false;
+    }
+
+    let start: Vec<u8> = body
+        .iter()
+        .copied()
+        .skip_while(|b| b.is_ascii_whitespace())
+        .take(512)
+        .collect();
+
+    const HTML_PREFIXES: &[&[u8]] = &[b"<!doctype html", b"<html", b"<head", b"<script"];
+    HTML_PREFIXES
+        .iter()
+        .any(|prefix| start.len() >= prefix.len() && start[..prefix.len()].eq_ignore_ascii_case(prefix))
+}
+
+/// Strips a leading UTF-8 or UTF-16 byte-order mark, if present.
+fn strip_bom(bytes: &[u8]) -> &[u8] {
+    if let Some(rest) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        rest
+    } else if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        rest
+    } else if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        rest
+    } else {
+        bytes
+    }
+}
+
+/// Recognises common binary magic numbers (GIF, PNG, JPEG, PDF, ZIP) so a
+/// mislabeled binary is treated as pass-through instead of fed to the rewriter.
+fn looks_like_binary(bytes: &[u8]) -> bool {
+    const MAGIC: &[&[u8]] = &[
+        b"GIF8",
+        &[0x89, b'P', b'N', b'G'],
+        &[0xFF, 0xD8, 0xFF],
+        b"%PDF",
+        &[b'P', b'K', 0x03, 0x04],
+    ];
+    MAGIC.iter().any(|magic| bytes.starts_with(magic))
+}
+
 fn rewrite_html(
     bytes: Bytes,
     skip_service_worker: bool,
@@ -866,6 +2158,29 @@ fn rewrite_html(
     Ok(output)
 }
 
+/// Matches `host` against the route table, returning the first rule whose
+/// pattern matches. A `*.` prefix matches any host ending in the remainder;
+/// everything else is an exact, case-insensitive match.
+fn match_route<'a>(routes: &'a [RouteRule], host: &str) -> Option<&'a RouteRule> {
+    routes.iter().find(|rule| {
+        if let Some(suffix) = rule.host.strip_prefix("*.") {
+            host.eq_ignore_ascii_case(suffix)
+                || host
+                    .to_ascii_lowercase()
+                    .ends_with(&format!(".{}", suffix.to_ascii_lowercase()))
+        } else {
+            host.eq_ignore_ascii_case(&rule.host)
+        }
+    })
+}
+
+fn parse_scheme(scheme: &str) -> Scheme {
+    match scheme.to_ascii_lowercase().as_str() {
+        "http" | "ws" => Scheme::HTTP,
+        _ => Scheme::HTTPS,
+    }
+}
+
 fn parse_route(subdomain: String) -> Route {
     if let Some(rest) = subdomain.strip_prefix("port-") {
         let segments: Vec<&str> = rest.split('-').collect();
@@ -1027,9 +2342,20 @@ fn is_loop_header(req: &Request<Body>) -> bool {
         .unwrap_or(false)
 }
 
-fn cors_response(status: StatusCode) -> Response<Body> {
+fn cors_response(status: StatusCode, req: &Request<Body>, origin: Option<&str>) -> Response<Body> {
     let mut headers = HeaderMap::new();
-    add_cors_headers(&mut headers);
+    add_cors_headers(&mut headers, origin);
+
+    // Echo the exact method/headers the browser asked for during preflight
+    // rather than a blanket wildcard, so credentialed cross-subdomain requests
+    // between workspaces are accepted.
+    if let Some(value) = req.headers().get("access-control-request-method") {
+        headers.insert("access-control-allow-methods", value.clone());
+    }
+    if let Some(value) = req.headers().get("access-control-request-headers") {
+        headers.insert("access-control-allow-headers", value.clone());
+    }
+
     let mut builder = Response::builder().status(status);
     let headers_mut = builder.headers_mut().unwrap();
     for (name, value) in headers.iter() {
@@ -1183,3 +2509,170 @@ fn service_worker_response() -> Response<Body> {
         .body(Body::from(SERVICE_WORKER_JS))
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_map(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(
+                http::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        map
+    }
+
+    #[test]
+    fn parse_cache_control_reads_all_directives() {
+        let headers = header_map(&[("cache-control", "private, max-age=120, no-cache")]);
+        let control = parse_cache_control(&headers);
+        assert!(control.private);
+        assert!(control.no_cache);
+        assert!(!control.no_store);
+        assert_eq!(control.max_age, Some(120));
+        assert_eq!(control.s_maxage, None);
+    }
+
+    #[test]
+    fn parse_cache_control_trims_quotes_and_whitespace() {
+        let headers = header_map(&[("cache-control", "  s-maxage=\"30\" , no-store ")]);
+        let control = parse_cache_control(&headers);
+        assert!(control.no_store);
+        assert_eq!(control.s_maxage, Some(30));
+    }
+
+    #[test]
+    fn expires_lifetime_is_relative_to_date() {
+        let headers = header_map(&[
+            ("date", "Mon, 01 Jan 2024 00:00:00 +0000"),
+            ("expires", "Mon, 01 Jan 2024 00:01:00 +0000"),
+        ]);
+        assert_eq!(expires_lifetime(&headers), Some(60));
+    }
+
+    #[test]
+    fn expires_lifetime_clamps_past_expiry_to_zero() {
+        let headers = header_map(&[
+            ("date", "Mon, 01 Jan 2024 00:01:00 +0000"),
+            ("expires", "Mon, 01 Jan 2024 00:00:00 +0000"),
+        ]);
+        assert_eq!(expires_lifetime(&headers), Some(0));
+    }
+
+    fn entry_with(freshness: Duration, must_revalidate: bool) -> CacheEntry {
+        CacheEntry {
+            status: StatusCode::OK,
+            version: Version::HTTP_11,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            stored_at: Utc::now(),
+            freshness,
+            must_revalidate,
+            etag: None,
+            last_modified: None,
+            vary: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn is_fresh_honours_lifetime_and_revalidation() {
+        assert!(entry_with(Duration::from_secs(60), false).is_fresh(Utc::now()));
+        assert!(!entry_with(Duration::from_secs(0), false).is_fresh(Utc::now()));
+        // no-cache forces revalidation even within the lifetime window.
+        assert!(!entry_with(Duration::from_secs(60), true).is_fresh(Utc::now()));
+    }
+
+    #[test]
+    fn sniff_detects_html_after_bom_and_whitespace() {
+        assert!(sniff_is_html(b"<!DOCTYPE html><html></html>"));
+        assert!(sniff_is_html(b"   \n\t<HTML>"));
+        let mut bom = vec![0xEF, 0xBB, 0xBF];
+        bom.extend_from_slice(b"  <head>");
+        assert!(sniff_is_html(&bom));
+    }
+
+    #[test]
+    fn sniff_rejects_plain_text_and_binaries() {
+        assert!(!sniff_is_html(b"just some text, not markup"));
+        assert!(!sniff_is_html(b"\x89PNG\r\n\x1a\n"));
+        assert!(!sniff_is_html(b"GIF89a"));
+    }
+
+    #[test]
+    fn binary_magic_numbers_are_recognised() {
+        assert!(looks_like_binary(b"%PDF-1.7"));
+        assert!(looks_like_binary(&[0xFF, 0xD8, 0xFF, 0xE0]));
+        assert!(looks_like_binary(&[b'P', b'K', 0x03, 0x04]));
+        assert!(!looks_like_binary(b"<html>"));
+    }
+
+    #[test]
+    fn strip_bom_removes_known_marks() {
+        assert_eq!(strip_bom(&[0xEF, 0xBB, 0xBF, b'x']), b"x");
+        assert_eq!(strip_bom(&[0xFF, 0xFE, b'x']), b"x");
+        assert_eq!(strip_bom(b"no bom"), b"no bom");
+    }
+
+    #[test]
+    fn cors_origin_matches_allowlist_and_zone() {
+        let allowlist = vec!["app.example.com".to_string()];
+        assert_eq!(
+            resolve_cors_origin(&allowlist, None, Some("https://app.example.com")),
+            Some("https://app.example.com".to_string())
+        );
+        assert_eq!(
+            resolve_cors_origin(&allowlist, Some("cmux.sh"), Some("https://foo.cmux.sh")),
+            Some("https://foo.cmux.sh".to_string())
+        );
+        assert_eq!(
+            resolve_cors_origin(&allowlist, Some("cmux.sh"), Some("https://evil.example.org")),
+            None
+        );
+        assert_eq!(resolve_cors_origin(&allowlist, Some("cmux.sh"), None), None);
+    }
+
+    #[test]
+    fn vary_origin_appends_without_duplicating() {
+        let mut headers = HeaderMap::new();
+        append_vary_origin(&mut headers);
+        assert_eq!(headers.get(header::VARY).unwrap(), "Origin");
+
+        let mut headers = header_map(&[("vary", "Accept-Encoding")]);
+        append_vary_origin(&mut headers);
+        assert_eq!(headers.get(header::VARY).unwrap(), "Accept-Encoding, Origin");
+
+        let mut headers = header_map(&[("vary", "origin")]);
+        append_vary_origin(&mut headers);
+        assert_eq!(headers.get(header::VARY).unwrap(), "origin");
+    }
+
+    #[test]
+    fn connection_tokens_are_split_and_lowercased() {
+        let headers = header_map(&[("connection", "keep-alive, Upgrade")]);
+        assert_eq!(connection_tokens(&headers), vec!["keep-alive", "upgrade"]);
+    }
+
+    #[test]
+    fn remove_hop_headers_drops_fixed_and_named_tokens() {
+        let headers = header_map(&[
+            ("connection", "x-custom"),
+            ("x-custom", "1"),
+            ("transfer-encoding", "chunked"),
+            ("content-type", "text/plain"),
+        ]);
+        let filtered = remove_hop_headers(&headers);
+        assert!(filtered.get("connection").is_none());
+        assert!(filtered.get("transfer-encoding").is_none());
+        assert!(filtered.get("x-custom").is_none());
+        assert_eq!(filtered.get("content-type").unwrap(), "text/plain");
+    }
+
+    #[test]
+    fn optimize_image_rejects_non_image_and_missing_avif() {
+        assert!(optimize_image(b"not an image", "text/plain", "image/avif", 4096).is_none());
+        assert!(optimize_image(b"\x89PNG", "image/png", "image/webp", 4096).is_none());
+    }
+}